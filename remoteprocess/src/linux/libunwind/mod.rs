@@ -9,10 +9,13 @@
 /// (currently we're using libunwind mainly to validate the gimli unwider)
 use libc::{c_int, c_void, c_char, size_t, pid_t};
 use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 mod bindings;
 
 use self::bindings::{unw_addr_space_t, unw_cursor, unw_accessors_t, unw_cursor_t, unw_regnum_t, unw_word_t,
+                     unw_proc_info_t, unw_fpreg_t,
                      unw_frame_regnum_t_UNW_REG_IP, unw_frame_regnum_t_UNW_REG_SP,
                      unw_caching_policy_t, unw_caching_policy_t_UNW_CACHE_PER_THREAD};
 
@@ -20,34 +23,148 @@ use self::bindings::{unw_addr_space_t, unw_cursor, unw_accessors_t, unw_cursor_t
 pub enum Error {
     /// libunwind call returned an error value
     LibunwindError(i32),
+    /// couldn't determine the ELF class of the target's executable
+    InvalidElf,
+    /// failed reading the target's executable to determine its ELF class
+    Io(std::io::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
 pub struct LibUnwind {
-    pub addr_space: unw_addr_space_t
+    /// Address spaces, created lazily and keyed by the `AddressSpaceBackend` (in turn, the ELF
+    /// class) of whatever targets they've been used to unwind so far.
+    addr_spaces: RefCell<HashMap<AddressSpaceBackend, unw_addr_space_t>>,
+    /// Address spaces for unwinding captured `Snapshot`s, bound to our own accessors (rather
+    /// than libunwind-ptrace's `_UPT_accessors`) and - like `addr_spaces` - created lazily and
+    /// keyed by `AddressSpaceBackend`, since a snapshot may have been captured from a process
+    /// of a different ELF class than whatever's unwinding it.
+    snapshot_addr_spaces: RefCell<HashMap<AddressSpaceBackend, unw_addr_space_t>>,
+    /// Per-return-address cache of the unwind descriptor discovered the first time that
+    /// address was visited, so repeat samples hitting the same PC skip the DWARF/CFI parse.
+    /// Must be cleared (see `invalidate_frame_cache`) whenever the target's module map
+    /// changes, since a descriptor is only valid for as long as the code backing it stays
+    /// mapped where it was when it got cached.
+    frame_cache: RefCell<HashMap<u64, FrameDescriptor>>,
+    /// The `AddressSpaceBackend` resolved for each pid we've unwound so far, so repeat
+    /// samples of the same target don't re-open `/proc/<pid>/exe` just to re-derive a value
+    /// that can't change for the lifetime of that process. Entries are only ever added, never
+    /// refreshed automatically - if a pid might have exited and been recycled by an unrelated
+    /// process of a different bitness, the caller must evict it first via
+    /// `invalidate_backend_for_pid`.
+    backend_for_pid: RefCell<HashMap<pid_t, AddressSpaceBackend>>
 }
 
 impl LibUnwind {
     pub fn new() -> Result<LibUnwind> {
-        unsafe {
-            let addr_space = create_addr_space(&_UPT_accessors as *const _ as *mut _, 0);
-            // enabling caching provides a modest speedup - but is still much slower than the gimli unwinding
-            set_caching_policy(addr_space, unw_caching_policy_t_UNW_CACHE_PER_THREAD);
-            Ok(LibUnwind{addr_space})
+        Ok(LibUnwind{
+            addr_spaces: RefCell::new(HashMap::new()),
+            snapshot_addr_spaces: RefCell::new(HashMap::new()),
+            frame_cache: RefCell::new(HashMap::new()),
+            backend_for_pid: RefCell::new(HashMap::new())
+        })
+    }
+
+    /// Looks up (or resolves and caches) the `FrameDescriptor` for the address the cursor is
+    /// currently stopped at.
+    pub fn frame_descriptor(&self, cursor: &Cursor) -> Result<FrameDescriptor> {
+        let ip = cursor.ip()?;
+        if let Some(descriptor) = self.frame_cache.borrow().get(&ip) {
+            return Ok(*descriptor);
+        }
+        let descriptor = cursor.proc_info()?;
+        self.frame_cache.borrow_mut().insert(ip, descriptor);
+        Ok(descriptor)
+    }
+
+    /// Drops all cached frame descriptors. Must be called whenever the target's module map
+    /// changes (a library gets loaded or unloaded), since a cached descriptor's CFA rule is
+    /// only valid for as long as the code backing it stays mapped where it was when cached.
+    pub fn invalidate_frame_cache(&self) {
+        self.frame_cache.borrow_mut().clear();
+    }
+
+    /// Returns the `AddressSpaceBackend` for `pid`, resolving and caching it on first use.
+    fn backend_for(&self, pid: pid_t) -> Result<AddressSpaceBackend> {
+        if let Some(&backend) = self.backend_for_pid.borrow().get(&pid) {
+            return Ok(backend);
+        }
+        let backend = AddressSpaceBackend::for_pid(pid)?;
+        self.backend_for_pid.borrow_mut().insert(pid, backend);
+        Ok(backend)
+    }
+
+    /// Evicts `pid` from the cached-backend table. Must be called once the caller knows `pid`
+    /// has exited, before that pid number can possibly be reused by an unrelated process -
+    /// otherwise `cursor(pid)` would go on dispatching the new process through whatever backend
+    /// the old one resolved to, mirroring `invalidate_frame_cache`'s module-map caveat.
+    pub fn invalidate_backend_for_pid(&self, pid: pid_t) {
+        self.backend_for_pid.borrow_mut().remove(&pid);
+    }
+
+    /// Returns the address space for `backend`, creating and caching it in `cache` on first use.
+    unsafe fn cached_addr_space(cache: &RefCell<HashMap<AddressSpaceBackend, unw_addr_space_t>>,
+                                 backend: AddressSpaceBackend, accessors: *mut unw_accessors_t) -> unw_addr_space_t {
+        if let Some(&addr_space) = cache.borrow().get(&backend) {
+            return addr_space;
         }
+
+        let addr_space = backend.create_addr_space(accessors, 0);
+        // enabling caching provides a modest speedup - but is still much slower than the gimli unwinding
+        backend.set_caching_policy(addr_space, unw_caching_policy_t_UNW_CACHE_PER_THREAD);
+        cache.borrow_mut().insert(backend, addr_space);
+        addr_space
     }
 
+    /// Returns the address space for `backend`, creating and caching it on first use.
+    unsafe fn addr_space_for(&self, backend: AddressSpaceBackend) -> unw_addr_space_t {
+        Self::cached_addr_space(&self.addr_spaces, backend, &_UPT_accessors as *const _ as *mut _)
+    }
+
+    /// Returns the snapshot-backed address space for `backend`, creating and caching it on
+    /// first use.
+    unsafe fn snapshot_addr_space_for(&self, backend: AddressSpaceBackend) -> unw_addr_space_t {
+        Self::cached_addr_space(&self.snapshot_addr_spaces, backend, &SNAPSHOT_ACCESSORS as *const _ as *mut _)
+    }
+
+    /// Unwinds the target `pid`. The target's ELF class (32 or 64 bit) determines which
+    /// `AddressSpaceBackend` can unwind it, so a single py-spy build can handle targets of
+    /// either bitness; the resolved backend is cached per-pid (see `backend_for_pid`) since a
+    /// process's bitness can't change, and re-deriving it would mean re-reading
+    /// `/proc/<pid>/exe` on every sample.
     pub fn cursor(&self, pid: pid_t) -> Result<Cursor> {
         unsafe
         {
+            let backend = self.backend_for(pid)?;
+            let addr_space = self.addr_space_for(backend);
+
             let upt = _UPT_create(pid as _);
             let mut cursor = std::mem::uninitialized();
-            let ret = init_remote(&mut cursor, self.addr_space, upt);
+            let ret = backend.init_remote(&mut cursor, addr_space, upt);
             if ret != 0 {
                 return Err(Error::LibunwindError(ret));
             }
-            Ok(Cursor{cursor, upt, initial_frame: true})
+            Ok(Cursor{cursor, backend, backing: CursorBacking::Ptrace(upt), initial_frame: true})
+        }
+    }
+
+    /// Unwinds a `Snapshot` captured earlier (possibly on another machine) instead of a live,
+    /// ptrace-stopped process. This decouples the fast part of sampling (copying registers and
+    /// stack memory) from the slow part (DWARF/CFI resolution), letting the latter happen off
+    /// the hot path. Dispatches on `snapshot.elf_class` (the bitness of whatever process it was
+    /// captured from) rather than assuming the snapshot matches this build's own architecture.
+    pub fn cursor_from_snapshot(&self, snapshot: Snapshot) -> Result<Cursor> {
+        unsafe {
+            let backend = AddressSpaceBackend::for_elf_class(snapshot.elf_class)?;
+            let addr_space = self.snapshot_addr_space_for(backend);
+            let arg = Box::into_raw(Box::new(snapshot));
+            let mut cursor = std::mem::uninitialized();
+            let ret = backend.init_remote(&mut cursor, addr_space, arg as *mut c_void);
+            if ret != 0 {
+                drop(Box::from_raw(arg));
+                return Err(Error::LibunwindError(ret));
+            }
+            Ok(Cursor{cursor, backend, backing: CursorBacking::Snapshot(arg), initial_frame: true})
         }
     }
 }
@@ -55,36 +172,182 @@ impl LibUnwind {
 impl Drop for LibUnwind {
     fn drop(&mut self) {
         unsafe {
-            destroy_addr_space(self.addr_space);
+            for (backend, addr_space) in self.addr_spaces.borrow().iter() {
+                backend.destroy_addr_space(*addr_space);
+            }
+            for (backend, addr_space) in self.snapshot_addr_spaces.borrow().iter() {
+                backend.destroy_addr_space(*addr_space);
+            }
         }
     }
 }
 
+/// A single contiguous range of memory copied out of a target process at snapshot time.
+pub struct MemoryRange {
+    pub start: u64,
+    pub data: Vec<u8>
+}
+
+/// The unwind info and symbol name libunwind resolved for one return address range, captured
+/// from a live `Cursor` (see `Cursor::captured_proc_info`) at snapshot time - before the pid it
+/// came from could exit or be recycled by an unrelated process. Mirrors `unw_proc_info_t` plus
+/// the symbol name, so `snapshot_find_proc_info`/`snapshot_get_proc_name` can replay exactly
+/// what libunwind itself resolved, without needing the original pid again.
+#[derive(Debug, Clone)]
+pub struct CapturedProcInfo {
+    pub start_ip: u64,
+    pub end_ip: u64,
+    pub lsda: u64,
+    pub handler: u64,
+    pub gp: u64,
+    pub flags: u64,
+    pub format: i32,
+    pub unwind_info_size: i32,
+    /// Address (in the captured process' address space) of the unwind-info table libunwind's
+    /// own CFI engine reads via `access_mem` - which `snapshot_access_mem` serves out of
+    /// `Snapshot::memory`, so this only resolves correctly if that range was captured too.
+    pub unwind_info: u64,
+    pub name: String,
+    /// Offset of the address this was captured for, from `start_ip`, as `get_proc_name` reports it.
+    pub name_offset: u64
+}
+
+/// A register set plus the stack/memory ranges that were live when a process was sampled,
+/// captured up front so that unwinding can happen later - off the hot path, or even on a
+/// different machine than the one the sample was taken on. All unwind info this needs (memory,
+/// registers, and proc info/symbol names) is captured up front, so resolving a `Snapshot` never
+/// has to go back and consult the pid it came from, which may have exited - or worse, been
+/// recycled for an unrelated process - by the time the snapshot is actually unwound.
+pub struct Snapshot {
+    /// The pid the snapshot was captured from. Purely informational (e.g. for logging) -
+    /// unwinding never uses it to look anything up live.
+    pub pid: pid_t,
+    /// The ELF class of the process this was captured from, so `cursor_from_snapshot` can pick
+    /// a matching `AddressSpaceBackend` instead of assuming the snapshot matches whatever
+    /// architecture is doing the unwinding.
+    pub elf_class: ElfClass,
+    pub registers: HashMap<unw_regnum_t, unw_word_t>,
+    pub memory: Vec<MemoryRange>,
+    /// Proc-info records resolved ahead of time (see `Cursor::captured_proc_info`) and looked up
+    /// by address range instead of by asking libunwind-ptrace to consult a live pid.
+    pub proc_info: Vec<CapturedProcInfo>
+}
+
+impl Snapshot {
+    fn read(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        for range in &self.memory {
+            let end = range.start + range.data.len() as u64;
+            if addr >= range.start && addr + (len as u64) <= end {
+                let offset = (addr - range.start) as usize;
+                return Some(&range.data[offset..offset + len]);
+            }
+        }
+        None
+    }
+
+    fn proc_info_for(&self, ip: u64) -> Option<&CapturedProcInfo> {
+        self.proc_info.iter().find(|info| ip >= info.start_ip && ip < info.end_ip)
+    }
+}
+
+/// A cached summary of the unwind info libunwind resolved for a given return address, so
+/// repeat visits to the same address don't have to re-parse the DWARF/CFI data.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameDescriptor {
+    pub start_ip: u64,
+    pub end_ip: u64,
+    pub format: i32
+}
+
 pub struct Cursor {
     cursor: unw_cursor,
-    upt: * mut c_void,
+    backend: AddressSpaceBackend,
+    backing: CursorBacking,
     initial_frame: bool
 }
 
+enum CursorBacking {
+    Ptrace(*mut c_void),
+    Snapshot(*mut Snapshot)
+}
+
 impl Cursor {
     pub unsafe fn register(&self, register: i32) -> Result<u64> {
         let mut value = 0;
         let cursor = &self.cursor as *const _ as *mut _;
 
-        match get_reg(cursor, register, &mut value) {
+        match self.backend.get_reg(cursor, register, &mut value) {
             0 => Ok(value),
             err => Err(Error::LibunwindError(err))
         }
     }
 
+    // register 3 is ebx/rbx in both the x86 and x86_64 DWARF register numbering
+    #[cfg(any(target_arch="x86_64", target_arch="x86"))]
     pub fn bx(&self) -> Result<u64> {
         unsafe { self.register(3) }
     }
 
+    // aarch64 has no direct equivalent of x86's ebx - the link register (x30, DWARF
+    // regnum 30) is the closest analogue for the frame validation this is used for
+    #[cfg(target_arch="aarch64")]
+    pub fn bx(&self) -> Result<u64> {
+        unsafe { self.register(30) }
+    }
+
+    // In libunwind's register numbering, regnum 16 is RIP on x86_64 and regnum 8 is EIP on
+    // (32-bit) x86 - unlike `bx()`, there's no number that happens to mean the same register in
+    // both numberings (regnum 7 is RSP/ESP in both, used by `sp_regnum()`). Dispatched on
+    // `self.backend` rather than just `target_arch`, since an x86_64 build's `AddressSpaceBackend::X86`
+    // can service a 32-bit target at runtime. `unw_frame_regnum_t_UNW_REG_IP` only reflects
+    // whichever arch's headers bindgen actually ran against (the build host's), so it's usable
+    // for the backend matching that arch but not for the other one - which gets a literal.
+    #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+    fn ip_regnum(&self) -> i32 {
+        match self.backend {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => unw_frame_regnum_t_UNW_REG_IP as i32,
+            #[cfg(target_arch="x86")]
+            AddressSpaceBackend::X86 => unw_frame_regnum_t_UNW_REG_IP as i32,
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86 => 8,
+        }
+    }
+
+    #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+    fn sp_regnum(&self) -> i32 {
+        match self.backend {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => unw_frame_regnum_t_UNW_REG_SP as i32,
+            #[cfg(target_arch="x86")]
+            AddressSpaceBackend::X86 => unw_frame_regnum_t_UNW_REG_SP as i32,
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86 => 7,
+        }
+    }
+
+    #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+    pub fn ip(&self) -> Result<u64> {
+        unsafe { self.register(self.ip_regnum()) }
+    }
+
+    // unw_frame_regnum_t_UNW_REG_IP is the program counter on aarch64 - pulled from the
+    // bindings rather than a literal DWARF regnum, unlike the x86/x86_64 `ip_regnum()` above
+    // which has to dispatch per-backend since a single build's bindgen constants only match
+    // one of the two arches it can unwind.
+    #[cfg(target_arch="aarch64")]
     pub fn ip(&self) -> Result<u64> {
         unsafe { self.register(unw_frame_regnum_t_UNW_REG_IP as i32) }
     }
 
+    #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+    pub fn sp(&self) -> Result<u64> {
+        unsafe { self.register(self.sp_regnum()) }
+    }
+
+    // unw_frame_regnum_t_UNW_REG_SP is the stack pointer on aarch64 - same reasoning as `ip()`
+    // above, there's only one arch's worth of bindgen constants to dispatch on here.
+    #[cfg(target_arch="aarch64")]
     pub fn sp(&self) -> Result<u64> {
         unsafe { self.register(unw_frame_regnum_t_UNW_REG_SP as i32) }
     }
@@ -96,7 +359,7 @@ impl Cursor {
             let mut raw_offset = std::mem::uninitialized();
 
             loop {
-                match get_proc_name(cursor, name.as_mut_ptr(), name.len(), &mut raw_offset) {
+                match self.backend.get_proc_name(cursor, name.as_mut_ptr(), name.len(), &mut raw_offset) {
                     0 => break,
                     // TODO: use -UNW_ENOMEM or something instead
                     -2 =>  {
@@ -112,6 +375,62 @@ impl Cursor {
             Ok(std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned())
         }
     }
+
+    fn proc_info(&self) -> Result<FrameDescriptor> {
+        unsafe {
+            let cursor = &self.cursor as *const _ as *mut _;
+            let mut info: unw_proc_info_t = std::mem::uninitialized();
+
+            match self.backend.get_proc_info(cursor, &mut info) {
+                0 => Ok(FrameDescriptor{start_ip: info.start_ip as u64, end_ip: info.end_ip as u64, format: info.format}),
+                err => Err(Error::LibunwindError(err))
+            }
+        }
+    }
+
+    /// Resolves the full `CapturedProcInfo` (bounds, CFI data and symbol name) for wherever this
+    /// cursor is currently stopped. Meant to be called on a live, ptrace-backed cursor while
+    /// walking a stack to be captured into a `Snapshot` - so that unwinding the snapshot later
+    /// never has to ask libunwind-ptrace to resolve proc info against the live pid again.
+    pub fn captured_proc_info(&self) -> Result<CapturedProcInfo> {
+        unsafe {
+            let cursor = &self.cursor as *const _ as *mut _;
+            let mut info: unw_proc_info_t = std::mem::uninitialized();
+
+            match self.backend.get_proc_info(cursor, &mut info) {
+                0 => {
+                    let name = self.proc_name().unwrap_or_default();
+                    let ip = self.ip()?;
+                    Ok(CapturedProcInfo{
+                        start_ip: info.start_ip as u64,
+                        end_ip: info.end_ip as u64,
+                        lsda: info.lsda as u64,
+                        handler: info.handler as u64,
+                        gp: info.gp as u64,
+                        flags: info.flags as u64,
+                        format: info.format,
+                        unwind_info_size: info.unwind_info_size,
+                        unwind_info: info.unwind_info as usize as u64,
+                        name,
+                        name_offset: ip.saturating_sub(info.start_ip as u64)
+                    })
+                },
+                err => Err(Error::LibunwindError(err))
+            }
+        }
+    }
+
+    /// Collects just the instruction-pointer chain for this stack, without calling
+    /// `get_proc_name` at all. Symbolizing every frame of every sample is one of the most
+    /// expensive parts of unwinding; callers that want names can resolve them afterwards
+    /// (and only once per unique address) instead of paying for it on every sample.
+    pub fn backtrace(self) -> Result<Vec<u64>> {
+        let mut ips = Vec::new();
+        for ip in self {
+            ips.push(ip?);
+        }
+        Ok(ips)
+    }
 }
 
 impl Iterator for Cursor {
@@ -122,7 +441,7 @@ impl Iterator for Cursor {
         // this isn't the first frame
         if !self.initial_frame {
             unsafe {
-                match step(&mut self.cursor) {
+                match self.backend.step(&mut self.cursor) {
                     0 => return None,
                     err if err < 0 => return Some(Err(Error::LibunwindError(err))),
                     _ => {}
@@ -143,7 +462,10 @@ impl Iterator for Cursor {
 impl Drop for Cursor {
     fn drop(&mut self) {
         unsafe {
-            _UPT_destroy(self.upt);
+            match self.backing {
+                CursorBacking::Ptrace(upt) => { _UPT_destroy(upt); },
+                CursorBacking::Snapshot(arg) => drop(Box::from_raw(arg))
+            }
         }
     }
 }
@@ -155,47 +477,401 @@ extern {
     static _UPT_accessors: unw_accessors_t;
 }
 
-#[cfg(target_pointer_width="64")]
-extern {
-    // functions in libunwind-x86_64.so (TODO: define similar for 32bit)
-     #[link_name="_Ux86_64_create_addr_space"]
-    fn create_addr_space(acc: *mut unw_accessors_t, byteorder: c_int) -> unw_addr_space_t;
-    #[link_name="_Ux86_64_destroy_addr_space"]
-    fn destroy_addr_space(addr: unw_addr_space_t) -> c_void;
-    #[link_name="_Ux86_64_init_remote"]
-    fn init_remote(cursor: *mut unw_cursor_t, addr: unw_addr_space_t, ptr: *mut c_void) -> c_int;
-    #[link_name="_Ux86_64_get_reg"]
-    fn get_reg(cursor: *mut unw_cursor_t, reg: unw_regnum_t, val: *mut unw_word_t) -> c_int;
-    #[link_name="_Ux86_64_step"]
-    fn step(cursor: *mut unw_cursor_t) -> c_int;
-    #[link_name="_Ux86_64_get_proc_name"]
-    fn get_proc_name(cursor: *mut unw_cursor, buffer: * mut c_char, len: size_t, offset: *mut unw_word_t) -> c_int;
-    #[link_name="_Ux86_64_set_caching_policy"]
-    fn set_caching_policy(spc: unw_addr_space_t, policy: unw_caching_policy_t) -> c_int;
-}
-
-#[cfg(target_pointer_width="32")]
-extern {
-     #[link_name="_Ux86_create_addr_space"]
-    fn create_addr_space(acc: *mut unw_accessors_t, byteorder: c_int) -> unw_addr_space_t;
-    #[link_name="_Ux86_destroy_addr_space"]
-    fn destroy_addr_space(addr: unw_addr_space_t) -> c_void;
-    #[link_name="_Ux86_init_remote"]
-    fn init_remote(cursor: *mut unw_cursor_t, addr: unw_addr_space_t, ptr: *mut c_void) -> c_int;
-    #[link_name="_Ux86_get_reg"]
-    fn get_reg(cursor: *mut unw_cursor_t, reg: unw_regnum_t, val: *mut unw_word_t) -> c_int;
-    #[link_name="_Ux86_step"]
-    fn step(cursor: *mut unw_cursor_t) -> c_int;
-    #[link_name="_Ux86_get_proc_name"]
-    fn get_proc_name(cursor: *mut unw_cursor, buffer: * mut c_char, len: size_t, offset: *mut unw_word_t) -> c_int;
-    #[link_name="_Ux86_set_caching_policy"]
-    fn set_caching_policy(spc: unw_addr_space_t, policy: unw_caching_policy_t) -> c_int;
+fn read_word(bytes: &[u8]) -> unw_word_t {
+    let mut value: unw_word_t = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as unw_word_t) << (i * 8);
+    }
+    value
+}
+
+// `arg` for a snapshot-backed cursor is just `*mut Snapshot` - unlike the ptrace-backed
+// `_UPT_accessors`, none of these callbacks forward into libunwind-ptrace's own
+// `_UPT_find_proc_info`/`_UPT_get_proc_name`/`_UPT_access_fpreg`, since those resolve unwind
+// info and symbol names by going back to `/proc/<pid>/maps` and ptrace against the *original*
+// pid - which may have exited, or (worse) been recycled into an unrelated process, by the time
+// a snapshot is actually unwound. Every callback below is served purely from data captured into
+// the `Snapshot` up front instead.
+
+unsafe extern "C" fn snapshot_access_mem(_as: unw_addr_space_t, addr: unw_word_t, val: *mut unw_word_t,
+                                         write: c_int, arg: *mut c_void) -> c_int {
+    if write != 0 {
+        // snapshots are a read-only view of memory copied out of the target earlier
+        return -1;
+    }
+    let snapshot = &*(arg as *const Snapshot);
+    match snapshot.read(addr as u64, std::mem::size_of::<unw_word_t>()) {
+        Some(bytes) => {
+            *val = read_word(bytes);
+            0
+        },
+        None => -1
+    }
+}
+
+unsafe extern "C" fn snapshot_access_reg(_as: unw_addr_space_t, regnum: unw_regnum_t, valp: *mut unw_word_t,
+                                         write: c_int, arg: *mut c_void) -> c_int {
+    if write != 0 {
+        return -1;
+    }
+    let snapshot = &*(arg as *const Snapshot);
+    match snapshot.registers.get(&regnum) {
+        Some(&value) => {
+            *valp = value;
+            0
+        },
+        None => -1
+    }
+}
+
+// Snapshots only capture general-purpose registers (`Snapshot::registers`), not floating-point
+// ones, so there's nothing to serve this from - fail cleanly rather than reach for a pid that
+// might not even exist anymore. Any CFI rule that needs a restored FP register will fail to
+// step past this frame, which is an honest, bounded limitation of snapshot-backed unwinding.
+unsafe extern "C" fn snapshot_access_fpreg(_as: unw_addr_space_t, _regnum: unw_regnum_t, _fpvalp: *mut unw_fpreg_t,
+                                           _write: c_int, _arg: *mut c_void) -> c_int {
+    -1
+}
+
+// Looks up the proc-info record `Cursor::captured_proc_info` resolved for this address while
+// the snapshot's source process was still alive, instead of re-deriving it (and needing that
+// pid to still exist) now.
+unsafe extern "C" fn snapshot_find_proc_info(_as: unw_addr_space_t, ip: unw_word_t, pi: *mut unw_proc_info_t,
+                                             _need_unwind_info: c_int, arg: *mut c_void) -> c_int {
+    let snapshot = &*(arg as *const Snapshot);
+    let info = match snapshot.proc_info_for(ip as u64) {
+        Some(info) => info,
+        None => return -1
+    };
+    *pi = std::mem::zeroed();
+    (*pi).start_ip = info.start_ip as unw_word_t;
+    (*pi).end_ip = info.end_ip as unw_word_t;
+    (*pi).lsda = info.lsda as unw_word_t;
+    (*pi).handler = info.handler as unw_word_t;
+    (*pi).gp = info.gp as unw_word_t;
+    (*pi).flags = info.flags as unw_word_t;
+    (*pi).format = info.format;
+    (*pi).unwind_info_size = info.unwind_info_size;
+    (*pi).unwind_info = info.unwind_info as usize as *mut c_void;
+    0
+}
+
+unsafe extern "C" fn snapshot_put_unwind_info(_as: unw_addr_space_t, _pi: *mut unw_proc_info_t, _arg: *mut c_void) {
+    // snapshot_find_proc_info doesn't allocate anything that needs releasing here
+}
+
+unsafe extern "C" fn snapshot_get_dyn_info_list_addr(_as: unw_addr_space_t, _dilap: *mut unw_word_t, _arg: *mut c_void) -> c_int {
+    // snapshots don't capture any JIT-registered (__register_frame) unwind tables
+    -1
+}
+
+unsafe extern "C" fn snapshot_resume(as_: unw_addr_space_t, cp: *mut unw_cursor_t, arg: *mut c_void) -> c_int {
+    // there's no live process to resume when unwinding a snapshot
+    let _ = (as_, cp, arg);
+    -1
+}
+
+// Looks up the name + offset `Cursor::captured_proc_info` resolved for this address while the
+// snapshot's source process was still alive, instead of consulting the (possibly gone, possibly
+// recycled) pid's `/proc/<pid>/maps` and symbol tables now.
+unsafe extern "C" fn snapshot_get_proc_name(_as: unw_addr_space_t, addr: unw_word_t, bufp: *mut c_char,
+                                            buf_len: size_t, offp: *mut unw_word_t, arg: *mut c_void) -> c_int {
+    let snapshot = &*(arg as *const Snapshot);
+    let info = match snapshot.proc_info_for(addr as u64) {
+        Some(info) => info,
+        None => return -1
+    };
+    let name_bytes = info.name.as_bytes();
+    // +1 for the nul terminator
+    if name_bytes.len() + 1 > buf_len {
+        // matches libunwind-ptrace's own -UNW_ENOMEM convention: Cursor::proc_name grows its
+        // buffer and retries on this
+        return -2;
+    }
+    std::ptr::copy_nonoverlapping(name_bytes.as_ptr() as *const c_char, bufp, name_bytes.len());
+    *bufp.add(name_bytes.len()) = 0;
+    *offp = info.name_offset as unw_word_t;
+    0
+}
+
+static SNAPSHOT_ACCESSORS: unw_accessors_t = unw_accessors_t {
+    find_proc_info: Some(snapshot_find_proc_info),
+    put_unwind_info: Some(snapshot_put_unwind_info),
+    get_dyn_info_list_addr: Some(snapshot_get_dyn_info_list_addr),
+    access_mem: Some(snapshot_access_mem),
+    access_reg: Some(snapshot_access_reg),
+    access_fpreg: Some(snapshot_access_fpreg),
+    resume: Some(snapshot_resume),
+    get_proc_name: Some(snapshot_get_proc_name),
+};
+
+// Each of these modules binds one arch's `_U<arch>_*` symbol family from its libunwind-<arch>.so.
+// We bind both the x86_64 and (32-bit) x86 families whenever either could plausibly be the
+// target - not just the one matching the host's pointer width - so that `AddressSpaceBackend`
+// can pick the right one at runtime based on the *target's* ELF class rather than the host's.
+
+#[cfg(target_arch="x86_64")]
+mod x86_64_raw {
+    use super::{c_int, c_void, c_char, size_t, unw_addr_space_t, unw_accessors_t, unw_cursor, unw_cursor_t,
+                unw_regnum_t, unw_word_t, unw_caching_policy_t, unw_proc_info_t};
+
+    extern {
+        // functions in libunwind-x86_64.so
+        #[link_name="_Ux86_64_create_addr_space"]
+        pub fn create_addr_space(acc: *mut unw_accessors_t, byteorder: c_int) -> unw_addr_space_t;
+        #[link_name="_Ux86_64_destroy_addr_space"]
+        pub fn destroy_addr_space(addr: unw_addr_space_t) -> c_void;
+        #[link_name="_Ux86_64_init_remote"]
+        pub fn init_remote(cursor: *mut unw_cursor_t, addr: unw_addr_space_t, ptr: *mut c_void) -> c_int;
+        #[link_name="_Ux86_64_get_reg"]
+        pub fn get_reg(cursor: *mut unw_cursor_t, reg: unw_regnum_t, val: *mut unw_word_t) -> c_int;
+        #[link_name="_Ux86_64_step"]
+        pub fn step(cursor: *mut unw_cursor_t) -> c_int;
+        #[link_name="_Ux86_64_get_proc_name"]
+        pub fn get_proc_name(cursor: *mut unw_cursor, buffer: * mut c_char, len: size_t, offset: *mut unw_word_t) -> c_int;
+        #[link_name="_Ux86_64_set_caching_policy"]
+        pub fn set_caching_policy(spc: unw_addr_space_t, policy: unw_caching_policy_t) -> c_int;
+        #[link_name="_Ux86_64_get_proc_info"]
+        pub fn get_proc_info(cursor: *mut unw_cursor_t, info: *mut unw_proc_info_t) -> c_int;
+    }
+}
+
+#[cfg(any(target_arch="x86_64", target_arch="x86"))]
+mod x86_raw {
+    use super::{c_int, c_void, c_char, size_t, unw_addr_space_t, unw_accessors_t, unw_cursor, unw_cursor_t,
+                unw_regnum_t, unw_word_t, unw_caching_policy_t, unw_proc_info_t};
+
+    extern {
+        // functions in libunwind-x86.so
+        #[link_name="_Ux86_create_addr_space"]
+        pub fn create_addr_space(acc: *mut unw_accessors_t, byteorder: c_int) -> unw_addr_space_t;
+        #[link_name="_Ux86_destroy_addr_space"]
+        pub fn destroy_addr_space(addr: unw_addr_space_t) -> c_void;
+        #[link_name="_Ux86_init_remote"]
+        pub fn init_remote(cursor: *mut unw_cursor_t, addr: unw_addr_space_t, ptr: *mut c_void) -> c_int;
+        #[link_name="_Ux86_get_reg"]
+        pub fn get_reg(cursor: *mut unw_cursor_t, reg: unw_regnum_t, val: *mut unw_word_t) -> c_int;
+        #[link_name="_Ux86_step"]
+        pub fn step(cursor: *mut unw_cursor_t) -> c_int;
+        #[link_name="_Ux86_get_proc_name"]
+        pub fn get_proc_name(cursor: *mut unw_cursor, buffer: * mut c_char, len: size_t, offset: *mut unw_word_t) -> c_int;
+        #[link_name="_Ux86_set_caching_policy"]
+        pub fn set_caching_policy(spc: unw_addr_space_t, policy: unw_caching_policy_t) -> c_int;
+        #[link_name="_Ux86_get_proc_info"]
+        pub fn get_proc_info(cursor: *mut unw_cursor_t, info: *mut unw_proc_info_t) -> c_int;
+    }
+}
+
+#[cfg(target_arch="aarch64")]
+mod aarch64_raw {
+    use super::{c_int, c_void, c_char, size_t, unw_addr_space_t, unw_accessors_t, unw_cursor, unw_cursor_t,
+                unw_regnum_t, unw_word_t, unw_caching_policy_t, unw_proc_info_t};
+
+    extern {
+        // functions in libunwind-aarch64.so
+        #[link_name="_Uaarch64_create_addr_space"]
+        pub fn create_addr_space(acc: *mut unw_accessors_t, byteorder: c_int) -> unw_addr_space_t;
+        #[link_name="_Uaarch64_destroy_addr_space"]
+        pub fn destroy_addr_space(addr: unw_addr_space_t) -> c_void;
+        #[link_name="_Uaarch64_init_remote"]
+        pub fn init_remote(cursor: *mut unw_cursor_t, addr: unw_addr_space_t, ptr: *mut c_void) -> c_int;
+        #[link_name="_Uaarch64_get_reg"]
+        pub fn get_reg(cursor: *mut unw_cursor_t, reg: unw_regnum_t, val: *mut unw_word_t) -> c_int;
+        #[link_name="_Uaarch64_step"]
+        pub fn step(cursor: *mut unw_cursor_t) -> c_int;
+        #[link_name="_Uaarch64_get_proc_name"]
+        pub fn get_proc_name(cursor: *mut unw_cursor, buffer: * mut c_char, len: size_t, offset: *mut unw_word_t) -> c_int;
+        #[link_name="_Uaarch64_set_caching_policy"]
+        pub fn set_caching_policy(spc: unw_addr_space_t, policy: unw_caching_policy_t) -> c_int;
+        #[link_name="_Uaarch64_get_proc_info"]
+        pub fn get_proc_info(cursor: *mut unw_cursor_t, info: *mut unw_proc_info_t) -> c_int;
+    }
+}
+
+/// The ELF class (pointer width) of a target's main executable, used to pick which libunwind
+/// address-space backend can correctly unwind it. Public so a `Snapshot` (which may have been
+/// captured on another machine, with no live pid to re-derive this from) can carry the class of
+/// the process it was captured from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElfClass {
+    Elf32,
+    Elf64
+}
+
+impl ElfClass {
+    /// Reads just the `e_ident` bytes of the target's main executable to determine its class,
+    /// without needing to parse the rest of the ELF file.
+    fn of_process(pid: pid_t) -> Result<ElfClass> {
+        use std::io::Read;
+
+        let mut ident = [0u8; 5];
+        std::fs::File::open(format!("/proc/{}/exe", pid)).and_then(|mut f| f.read_exact(&mut ident)).map_err(Error::Io)?;
+
+        if &ident[0..4] != b"\x7fELF" {
+            return Err(Error::InvalidElf);
+        }
+        match ident[4] {
+            1 => Ok(ElfClass::Elf32),
+            2 => Ok(ElfClass::Elf64),
+            _ => Err(Error::InvalidElf)
+        }
+    }
+}
+
+/// Which libunwind address-space backend (and in turn, which `_U<arch>_*` symbol family) to use
+/// for a given target. Chosen at runtime from the target's ELF class rather than baked in at
+/// compile time from `target_pointer_width`, so a single (64-bit) py-spy build can unwind both
+/// 32-bit and 64-bit targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AddressSpaceBackend {
+    #[cfg(target_arch="x86_64")]
+    X86_64,
+    #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+    X86,
+    #[cfg(target_arch="aarch64")]
+    AArch64
+}
+
+impl AddressSpaceBackend {
+    #[cfg(target_arch="x86_64")]
+    fn for_elf_class(class: ElfClass) -> Result<AddressSpaceBackend> {
+        Ok(match class {
+            ElfClass::Elf32 => AddressSpaceBackend::X86,
+            ElfClass::Elf64 => AddressSpaceBackend::X86_64
+        })
+    }
+
+    // A 32-bit build only binds the x86 (not x86_64) symbol family, so it can't service a
+    // 64-bit target regardless of what ElfClass says - but it still needs to check, rather
+    // than silently handing back the 32-bit backend and letting libunwind walk a 64-bit
+    // process with 32-bit register/address-space assumptions.
+    #[cfg(target_arch="x86")]
+    fn for_elf_class(class: ElfClass) -> Result<AddressSpaceBackend> {
+        match class {
+            ElfClass::Elf32 => Ok(AddressSpaceBackend::X86),
+            ElfClass::Elf64 => Err(Error::InvalidElf)
+        }
+    }
+
+    // aarch64 has no 32-bit (arm) backend bound here, so the same reasoning as the x86 build
+    // above applies: only Elf64 (aarch64) targets can actually be serviced.
+    #[cfg(target_arch="aarch64")]
+    fn for_elf_class(class: ElfClass) -> Result<AddressSpaceBackend> {
+        match class {
+            ElfClass::Elf64 => Ok(AddressSpaceBackend::AArch64),
+            ElfClass::Elf32 => Err(Error::InvalidElf)
+        }
+    }
+
+    /// Resolves the backend that can unwind `pid`, from its ELF class.
+    fn for_pid(pid: pid_t) -> Result<AddressSpaceBackend> {
+        AddressSpaceBackend::for_elf_class(ElfClass::of_process(pid)?)
+    }
+
+    /// The backend matching the architecture py-spy itself was compiled for. Not used by
+    /// `cursor_from_snapshot`, which picks its backend from `Snapshot::elf_class` instead.
+    #[cfg(target_arch="x86_64")]
+    fn native() -> AddressSpaceBackend { AddressSpaceBackend::X86_64 }
+    #[cfg(target_arch="x86")]
+    fn native() -> AddressSpaceBackend { AddressSpaceBackend::X86 }
+    #[cfg(target_arch="aarch64")]
+    fn native() -> AddressSpaceBackend { AddressSpaceBackend::AArch64 }
+
+    unsafe fn create_addr_space(self, acc: *mut unw_accessors_t, byteorder: c_int) -> unw_addr_space_t {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::create_addr_space(acc, byteorder),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::create_addr_space(acc, byteorder),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::create_addr_space(acc, byteorder)
+        }
+    }
+
+    unsafe fn destroy_addr_space(self, addr: unw_addr_space_t) {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => { x86_64_raw::destroy_addr_space(addr); },
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => { x86_raw::destroy_addr_space(addr); },
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => { aarch64_raw::destroy_addr_space(addr); }
+        }
+    }
+
+    unsafe fn init_remote(self, cursor: *mut unw_cursor_t, addr: unw_addr_space_t, ptr: *mut c_void) -> c_int {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::init_remote(cursor, addr, ptr),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::init_remote(cursor, addr, ptr),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::init_remote(cursor, addr, ptr)
+        }
+    }
+
+    unsafe fn get_reg(self, cursor: *mut unw_cursor_t, reg: unw_regnum_t, val: *mut unw_word_t) -> c_int {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::get_reg(cursor, reg, val),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::get_reg(cursor, reg, val),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::get_reg(cursor, reg, val)
+        }
+    }
+
+    unsafe fn step(self, cursor: *mut unw_cursor_t) -> c_int {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::step(cursor),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::step(cursor),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::step(cursor)
+        }
+    }
+
+    unsafe fn get_proc_name(self, cursor: *mut unw_cursor, buffer: *mut c_char, len: size_t, offset: *mut unw_word_t) -> c_int {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::get_proc_name(cursor, buffer, len, offset),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::get_proc_name(cursor, buffer, len, offset),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::get_proc_name(cursor, buffer, len, offset)
+        }
+    }
+
+    unsafe fn get_proc_info(self, cursor: *mut unw_cursor_t, info: *mut unw_proc_info_t) -> c_int {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::get_proc_info(cursor, info),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::get_proc_info(cursor, info),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::get_proc_info(cursor, info)
+        }
+    }
+
+    unsafe fn set_caching_policy(self, spc: unw_addr_space_t, policy: unw_caching_policy_t) -> c_int {
+        match self {
+            #[cfg(target_arch="x86_64")]
+            AddressSpaceBackend::X86_64 => x86_64_raw::set_caching_policy(spc, policy),
+            #[cfg(any(target_arch="x86_64", target_arch="x86"))]
+            AddressSpaceBackend::X86 => x86_raw::set_caching_policy(spc, policy),
+            #[cfg(target_arch="aarch64")]
+            AddressSpaceBackend::AArch64 => aarch64_raw::set_caching_policy(spc, policy)
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
-            Error::LibunwindError(e) => write!(f, "libunwind error {}", e)
+            Error::LibunwindError(e) => write!(f, "libunwind error {}", e),
+            Error::InvalidElf => write!(f, "unrecognized ELF class"),
+            Error::Io(ref e) => write!(f, "failed reading target executable: {}", e)
         }
     }
 }
@@ -203,11 +879,16 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {
     fn description(&self) -> &str {
         match *self {
-            Error::LibunwindError(_) => "LibunwindError"
+            Error::LibunwindError(_) => "LibunwindError",
+            Error::InvalidElf => "InvalidElf",
+            Error::Io(_) => "Io"
         }
     }
 
     fn cause(&self) -> Option<&std::error::Error> {
-        None
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None
+        }
     }
 }